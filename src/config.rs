@@ -0,0 +1,222 @@
+//! Configuration types consumed by `relay::tcprelay::http_local`.
+//!
+//! This reproduces the slice of the real `Config`/`ServerConfig` relevant to the local HTTP
+//! proxy; ciphers, plugins, and the rest of the server-side configuration live alongside this
+//! in the full configuration module.
+
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
+
+use ipnetwork::IpNetwork;
+
+/// Address of a remote Shadowsocks server.
+#[derive(Clone, Debug)]
+pub enum ServerAddr {
+    SocketAddr(SocketAddr),
+    DomainName(String, u16),
+}
+
+impl fmt::Display for ServerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerAddr::SocketAddr(addr) => write!(f, "{}", addr),
+            ServerAddr::DomainName(domain, port) => write!(f, "{}:{}", domain, port),
+        }
+    }
+}
+
+/// Configuration for a single remote Shadowsocks server.
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    addr: ServerAddr,
+}
+
+impl ServerConfig {
+    pub fn addr(&self) -> &ServerAddr {
+        &self.addr
+    }
+}
+
+/// Chains the connection to the Shadowsocks server through an upstream HTTP/HTTPS `CONNECT`
+/// proxy (e.g. a corporate egress proxy) before the Shadowsocks handshake.
+#[derive(Clone, Debug)]
+pub struct UpstreamProxyConfig {
+    pub addr: SocketAddr,
+    pub proxy_authorization: Option<String>,
+}
+
+impl UpstreamProxyConfig {
+    pub fn new(addr: SocketAddr, username: Option<&str>, password: Option<&str>) -> UpstreamProxyConfig {
+        let proxy_authorization = match (username, password) {
+            (Some(user), Some(pass)) => Some(format!("Basic {}", base64::encode(format!("{}:{}", user, pass)))),
+            _ => None,
+        };
+
+        UpstreamProxyConfig { addr, proxy_authorization }
+    }
+}
+
+/// A configured local source address, or a CIDR range to pick one from per connection, used to
+/// bind outbound sockets before connecting. Useful for egress IP rotation or for pinning
+/// traffic to a specific interface.
+#[derive(Clone, Debug)]
+pub enum SourceBind {
+    Fixed(IpAddr),
+    Cidr(IpNetwork),
+}
+
+impl SourceBind {
+    /// Picks a source address to bind for a connection to `target`, or `None` if `target`'s
+    /// address family doesn't match this `SourceBind`'s configured family -- the caller should
+    /// then report a configuration error rather than attempt a cross-family bind.
+    pub fn pick_addr(&self, target: SocketAddr) -> Option<IpAddr> {
+        let want_v6 = target.is_ipv6();
+
+        match self {
+            SourceBind::Fixed(addr) if addr.is_ipv6() == want_v6 => Some(*addr),
+            SourceBind::Fixed(_) => None,
+            SourceBind::Cidr(IpNetwork::V4(v4)) if !want_v6 => {
+                let network = u32::from(v4.network());
+                let broadcast = u32::from(v4.broadcast());
+                let span = broadcast.saturating_sub(network);
+                let offset = if span == 0 { 0 } else { rand::random::<u32>() % (span + 1) };
+                Some(IpAddr::V4(Ipv4Addr::from(network + offset)))
+            }
+            SourceBind::Cidr(IpNetwork::V6(v6)) if want_v6 => {
+                let network = u128::from(v6.network());
+                let host_bits = 128 - u32::from(v6.prefix());
+                let span: u128 = if host_bits >= 128 { u128::MAX } else { (1u128 << host_bits) - 1 };
+                let offset = if span == 0 { 0 } else { rand::random::<u128>() % (span + 1) };
+                Some(IpAddr::V6(Ipv6Addr::from(network + offset)))
+            }
+            SourceBind::Cidr(..) => None,
+        }
+    }
+}
+
+/// Certificate/key pair for terminating TLS on the local HTTP proxy listener, turning it into
+/// an HTTPS proxy. PEM-encoded, loaded by `relay::tcprelay::http_local::build_tls_acceptor`.
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+/// Which async resolver implementation backs a `DnsCache`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DnsResolverKind {
+    /// The system resolver (`tokio::net::lookup_host`).
+    Gai,
+    /// `trust-dns-resolver`, built from its default configuration (its own default upstream
+    /// servers, not `/etc/resolv.conf`). Requires the `trust-dns` feature.
+    #[cfg(feature = "trust-dns")]
+    TrustDns,
+}
+
+impl Default for DnsResolverKind {
+    fn default() -> DnsResolverKind {
+        DnsResolverKind::Gai
+    }
+}
+
+/// Settings for the domain-name cache consulted by `host_addr` targets for ACL/bypass
+/// decisions. Absent means no caching, and bypass decisions are made on the name, not the IP.
+#[derive(Clone, Debug)]
+pub struct DnsCacheSettings {
+    pub resolver: DnsResolverKind,
+    pub capacity: usize,
+    pub ttl: Duration,
+}
+
+/// Local HTTP(S) proxy configuration consumed by `relay::tcprelay::http_local`.
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    pub local: Option<SocketAddr>,
+    pub http_upstream_proxy: Option<UpstreamProxyConfig>,
+    pub http_tls: Option<TlsConfig>,
+    /// `(username, password)` pairs accepted on `Proxy-Authorization: Basic ...`. Empty means
+    /// proxy authentication is disabled.
+    pub http_proxy_auth: Option<Vec<(String, String)>>,
+    pub source_bind: Option<SourceBind>,
+    pub dns_cache: Option<DnsCacheSettings>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SourceBind;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+    fn v4(addr: &str) -> SocketAddr {
+        SocketAddr::new(addr.parse().unwrap(), 0)
+    }
+
+    fn v6(addr: &str) -> SocketAddr {
+        SocketAddr::new(addr.parse().unwrap(), 0)
+    }
+
+    #[test]
+    fn fixed_rejects_mismatched_family() {
+        let bind = SourceBind::Fixed(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(bind.pick_addr(v6("::1")), None);
+    }
+
+    #[test]
+    fn fixed_returns_itself_for_matching_family() {
+        let addr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let bind = SourceBind::Fixed(addr);
+        assert_eq!(bind.pick_addr(v4("93.184.216.34")), Some(addr));
+    }
+
+    #[test]
+    fn v4_cidr_picks_within_range() {
+        let bind = SourceBind::Cidr("10.0.0.0/30".parse().unwrap());
+        for _ in 0..50 {
+            match bind.pick_addr(v4("93.184.216.34")) {
+                Some(IpAddr::V4(addr)) => {
+                    let octets = addr.octets();
+                    assert_eq!(&octets[..3], &[10, 0, 0]);
+                    assert!(octets[3] <= 3);
+                }
+                other => panic!("expected a v4 address within 10.0.0.0/30, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn v4_cidr_rejects_v6_target() {
+        let bind = SourceBind::Cidr("10.0.0.0/30".parse().unwrap());
+        assert_eq!(bind.pick_addr(v6("::1")), None);
+    }
+
+    #[test]
+    fn v6_cidr_picks_within_range_and_varies() {
+        let bind = SourceBind::Cidr("2001:db8::/64".parse().unwrap());
+        let mut saw_different = false;
+        let mut prev = None;
+        for _ in 0..50 {
+            match bind.pick_addr(v6("2606:2800:220:1:248:1893:25c8:1946")) {
+                Some(IpAddr::V6(addr)) => {
+                    assert!(addr.segments()[..4] == Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0).segments()[..4]);
+                    if let Some(p) = prev {
+                        if p != addr {
+                            saw_different = true;
+                        }
+                    }
+                    prev = Some(addr);
+                }
+                other => panic!("expected a v6 address within 2001:db8::/64, got {:?}", other),
+            }
+        }
+        assert!(saw_different, "expected pick_addr to rotate across calls");
+    }
+
+    #[test]
+    fn v6_cidr_rejects_v4_target() {
+        let bind = SourceBind::Cidr("2001:db8::/64".parse().unwrap());
+        assert_eq!(bind.pick_addr(v4("93.184.216.34")), None);
+    }
+}
@@ -0,0 +1,22 @@
+//! Shared runtime context consumed by `relay::tcprelay::http_local`.
+
+use std::sync::Arc;
+
+use crate::{acl::AccessControl, config::Config};
+
+pub struct Context {
+    config: Config,
+    acl: Option<AccessControl>,
+}
+
+impl Context {
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn acl(&self) -> Option<&AccessControl> {
+        self.acl.as_ref()
+    }
+}
+
+pub type SharedContext = Arc<Context>;
@@ -1,24 +1,31 @@
 //! HTTP Proxy client server
 
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     convert::Infallible,
+    fs::File,
     future::Future,
-    io,
+    io::{self, BufReader},
     net::{IpAddr, SocketAddr},
     pin::Pin,
-    sync::Arc,
+    sync::{Arc, Mutex},
     task::{self, Poll},
+    time::{Duration, Instant},
 };
 
 use futures::{
     future,
     future::{BoxFuture, Either},
     FutureExt,
+    Stream,
+    StreamExt,
 };
 use hyper::{
-    client::connect::{Connected, Connection},
-    server::conn::AddrStream,
+    client::{
+        connect::{Connected, Connection},
+        HttpConnector,
+    },
+    server::{accept, conn::AddrStream},
     service::{make_service_fn, service_fn},
     upgrade::Upgraded,
     Body,
@@ -33,11 +40,20 @@ use hyper::{
 use log::{debug, error, info, trace};
 use pin_project::pin_project;
 use tokio;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpSocket, TcpStream},
+    sync::mpsc,
+};
+use tokio_rustls::{rustls, server::TlsStream, TlsAcceptor};
+use tokio_stream::wrappers::ReceiverStream;
 use tower;
 
+use lru::LruCache;
+
 use super::{CryptoStream, STcpStream};
 use crate::{
-    config::ServerConfig,
+    config::{DnsResolverKind, ServerConfig, SourceBind, TlsConfig, UpstreamProxyConfig},
     context::SharedContext,
     relay::{
         loadbalancing::server::{ping, LoadBalancer, PingBalancer},
@@ -45,18 +61,230 @@ use crate::{
     },
 };
 
+/// Default cap on the number of idle, handshaked connections kept around per pool key.
+const DEFAULT_POOL_MAX_IDLE_PER_KEY: usize = 8;
+/// Default duration an idle pooled connection is considered reusable before it is discarded.
+const DEFAULT_POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Bound on the number of TLS-handshaked connections buffered in the channel between
+/// `tls_incoming`'s accept loop and the `Server`. Handshake tasks are not limited by it: once the
+/// channel is full, further handshaked connections wait in their own tasks until hyper takes one.
+const DEFAULT_TLS_ACCEPT_BACKLOG: usize = 64;
+/// Backoff slept before retrying `listener.accept()` after a transient error (e.g. EMFILE),
+/// mirroring hyper's own `AddrIncoming` accept loop.
+const TLS_ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(50);
+
+struct PooledStream<T> {
+    stream: T,
+    idle_at: Instant,
+}
+
+/// A small LRU-ish pool of handshaked, but not-yet-consumed, connections.
+///
+/// Entries are keyed by `"{server addr}|{target addr}"`, since a handshaked stream already
+/// carries the target address in the relay header and can only be reused for the same target.
+/// Generic over the stored stream type so the eviction/capacity bookkeeping can be exercised in
+/// tests without a real `CryptoStream`.
+struct ConnectionPool<T> {
+    idle: Mutex<HashMap<String, VecDeque<PooledStream<T>>>>,
+    max_idle_per_key: usize,
+    idle_timeout: Duration,
+}
+
+/// Builds a `ConnectionPool` key for a `(server, target)` pair.
+fn pool_key(svr_cfg: &ServerConfig, addr: &Address) -> String {
+    format!("{}|{}", svr_cfg.addr(), addr)
+}
+
+impl<T> ConnectionPool<T> {
+    fn new(max_idle_per_key: usize, idle_timeout: Duration) -> ConnectionPool<T> {
+        ConnectionPool {
+            idle: Mutex::new(HashMap::new()),
+            max_idle_per_key,
+            idle_timeout,
+        }
+    }
+
+    /// Pops a still-fresh pooled connection for `key`, dropping any expired entries found
+    /// along the way.
+    fn take(&self, key: &str) -> Option<T> {
+        let mut idle = self.idle.lock().expect("connection pool lock poisoned");
+
+        let queue = idle.get_mut(key)?;
+        while let Some(pooled) = queue.pop_front() {
+            if pooled.idle_at.elapsed() < self.idle_timeout {
+                return Some(pooled.stream);
+            }
+            // Expired: drop it and keep looking for a fresher one.
+        }
+
+        None
+    }
+
+    /// Adds a handshaked-but-unused connection to the pool for `key`, unless the pool for
+    /// that key is already full, in which case the connection is simply dropped.
+    fn put(&self, key: String, stream: T) {
+        let mut idle = self.idle.lock().expect("connection pool lock poisoned");
+
+        let queue = idle.entry(key).or_insert_with(VecDeque::new);
+        if queue.len() < self.max_idle_per_key {
+            queue.push_back(PooledStream {
+                stream,
+                idle_at: Instant::now(),
+            });
+        }
+    }
+}
+
+/// Tunnels a TCP connection through an upstream HTTP/HTTPS proxy via `CONNECT`. Produces the
+/// raw stream to the final `target`, which the caller treats as a directly-dialed connection.
+/// The connection to the upstream proxy itself is plain TCP; TLS to the proxy is not supported.
+#[derive(Clone)]
+struct UpstreamProxyTunnel {
+    upstream: UpstreamProxyConfig,
+}
+
+impl UpstreamProxyTunnel {
+    fn new(upstream: UpstreamProxyConfig) -> UpstreamProxyTunnel {
+        UpstreamProxyTunnel { upstream }
+    }
+
+    async fn connect<T: std::fmt::Display>(&self, target: T, source_bind: Option<&SourceBind>) -> io::Result<TcpStream> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut stream = connect_with_source_bind(self.upstream.addr, source_bind).await?;
+
+        let target = target.to_string();
+        let mut req = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n", target = target);
+        if let Some(ref auth) = self.upstream.proxy_authorization {
+            req.push_str(&format!("Proxy-Authorization: {}\r\n", auth));
+        }
+        req.push_str("\r\n");
+
+        stream.write_all(req.as_bytes()).await?;
+
+        // Read the status line + headers up to the terminating blank line.
+        let mut buf = Vec::with_capacity(256);
+        let mut byte = [0u8; 1];
+        loop {
+            if stream.read(&mut byte).await? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "upstream proxy closed connection before completing CONNECT",
+                ));
+            }
+
+            buf.push(byte[0]);
+            if buf.ends_with(b"\r\n\r\n") {
+                break;
+            }
+            if buf.len() > 8192 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "upstream proxy response too large"));
+            }
+        }
+
+        let status_line = buf.split(|&b| b == b'\n').next().unwrap_or(&[]);
+        let status_line = String::from_utf8_lossy(status_line);
+        if !status_line.contains(" 200") {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("upstream proxy CONNECT failed: {}", status_line.trim()),
+            ));
+        }
+
+        Ok(stream)
+    }
+}
+
+/// Dials `addr`, binding the local end of the socket to a configured source address first, when
+/// one is set. Fails with `InvalidInput` rather than attempting the bind if `source`'s address
+/// family doesn't match `addr`'s.
+async fn connect_with_source_bind(addr: SocketAddr, source: Option<&SourceBind>) -> io::Result<TcpStream> {
+    let source = match source {
+        Some(source) => source,
+        None => return TcpStream::connect(addr).await,
+    };
+
+    let bind_addr = source
+        .pick_addr(addr)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("source_bind family does not match target {}", addr)))?;
+
+    let socket = match addr {
+        SocketAddr::V4(..) => TcpSocket::new_v4()?,
+        SocketAddr::V6(..) => TcpSocket::new_v6()?,
+    };
+    socket.bind(SocketAddr::new(bind_addr, 0))?;
+    socket.connect(addr).await
+}
+
+/// Resolves `host` (an `Address`'s string form, or a plain `host:port`) to its first candidate
+/// `SocketAddr`.
+async fn resolve_first_addr(host: &str) -> io::Result<SocketAddr> {
+    tokio::net::lookup_host(host)
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("failed to resolve {}", host)))
+}
+
+/// Resolves `addr` to the `SocketAddr` to dial. If `addr` is already a resolved
+/// `SocketAddress` (e.g. from a `BypassDecision`'s DNS-cache lookup), uses it directly instead
+/// of re-resolving the name, so the dial target matches the address an ACL decision was made on.
+async fn dial_addr(addr: &Address) -> io::Result<SocketAddr> {
+    match addr {
+        Address::SocketAddress(sockaddr) => Ok(*sockaddr),
+        Address::DomainNameAddress(..) => resolve_first_addr(&addr.to_string()).await,
+    }
+}
+
+/// Dials the Shadowsocks server: through a `CONNECT` tunnel on `context`'s configured upstream
+/// HTTP proxy when one is set, otherwise directly from the configured `source_bind` address
+/// when one is set, and otherwise with the usual `connect_proxy_server` dial.
+async fn connect_proxy_server_upstream_aware(context: &SharedContext, svr_cfg: &ServerConfig) -> io::Result<STcpStream> {
+    let source_bind = context.config().source_bind.as_ref();
+
+    match context.config().http_upstream_proxy.as_ref() {
+        Some(upstream) => {
+            let tunnel = UpstreamProxyTunnel::new(upstream.clone());
+            let stream = tunnel.connect(svr_cfg.addr(), source_bind).await?;
+            Ok(STcpStream::from(stream))
+        }
+        None if source_bind.is_some() => {
+            let addr = resolve_first_addr(&svr_cfg.addr().to_string()).await?;
+            let stream = connect_with_source_bind(addr, source_bind).await?;
+            Ok(STcpStream::from(stream))
+        }
+        None => super::connect_proxy_server(&**context, svr_cfg).await,
+    }
+}
+
+type CryptoConnectionPool = ConnectionPool<CryptoStream<STcpStream>>;
+
 #[derive(Clone)]
 struct ShadowSocksConnector {
     context: SharedContext,
     svr_cfg: Arc<ServerConfig>,
+    pool: Arc<CryptoConnectionPool>,
 }
 
 impl ShadowSocksConnector {
-    fn new(context: SharedContext, svr_cfg: Arc<ServerConfig>) -> ShadowSocksConnector {
-        ShadowSocksConnector { context, svr_cfg }
+    fn new(context: SharedContext, svr_cfg: Arc<ServerConfig>, pool: Arc<CryptoConnectionPool>) -> ShadowSocksConnector {
+        ShadowSocksConnector { context, svr_cfg, pool }
     }
 }
 
+/// After a cache miss, opportunistically dials and handshakes one spare connection for `key`
+/// in the background so the *next* request to the same (server, target) pair can skip the
+/// handshake. The spare is never handed to a caller directly, so it's always still fresh and
+/// safe to pool -- unlike a stream a caller has already started using.
+fn prewarm_pool(context: SharedContext, svr_cfg: Arc<ServerConfig>, addr: Address, pool: Arc<CryptoConnectionPool>, key: String) {
+    tokio::spawn(async move {
+        if let Ok(stream) = connect_proxy_server_upstream_aware(&context, &svr_cfg).await {
+            if let Ok(stream) = super::proxy_server_handshake(stream, svr_cfg, &addr).await {
+                pool.put(key, stream);
+            }
+        }
+    });
+}
+
 impl tower::Service<Address> for ShadowSocksConnector {
     type Error = io::Error;
     type Future = ShadowSocksConnecting;
@@ -69,11 +297,19 @@ impl tower::Service<Address> for ShadowSocksConnector {
     fn call(&mut self, addr: Address) -> Self::Future {
         let svr_cfg = self.svr_cfg.clone();
         let context = self.context.clone();
+        let pool = self.pool.clone();
 
         ShadowSocksConnecting {
             fut: async move {
-                let stream = super::connect_proxy_server(&*context, &*svr_cfg).await?;
-                super::proxy_server_handshake(stream, svr_cfg.clone(), &addr).await
+                let key = pool_key(&svr_cfg, &addr);
+                if let Some(stream) = pool.take(&key) {
+                    return Ok(stream);
+                }
+
+                let stream = connect_proxy_server_upstream_aware(&context, &*svr_cfg).await?;
+                let stream = super::proxy_server_handshake(stream, svr_cfg.clone(), &addr).await?;
+                prewarm_pool(context, svr_cfg, addr, pool, key);
+                Ok(stream)
             }
             .boxed(),
         }
@@ -92,6 +328,7 @@ impl tower::Service<Uri> for ShadowSocksConnector {
     fn call(&mut self, dst: Uri) -> Self::Future {
         let svr_cfg = self.svr_cfg.clone();
         let context = self.context.clone();
+        let pool = self.pool.clone();
 
         ShadowSocksConnecting {
             fut: async move {
@@ -105,8 +342,15 @@ impl tower::Service<Uri> for ShadowSocksConnector {
                         Err(err)
                     }
                     Some(addr) => {
-                        let stream = super::connect_proxy_server(&*context, &*svr_cfg).await?;
-                        super::proxy_server_handshake(stream, svr_cfg.clone(), &addr).await
+                        let key = pool_key(&svr_cfg, &addr);
+                        if let Some(stream) = pool.take(&key) {
+                            return Ok(stream);
+                        }
+
+                        let stream = connect_proxy_server_upstream_aware(&context, &*svr_cfg).await?;
+                        let stream = super::proxy_server_handshake(stream, svr_cfg.clone(), &addr).await?;
+                        prewarm_pool(context, svr_cfg, addr, pool, key);
+                        Ok(stream)
                     }
                 }
             }
@@ -239,15 +483,272 @@ async fn establish_connect_tunnel(
     debug!("CONNECT relay {} <-> {} ({}) closed", client_addr, svr_cfg.addr(), addr);
 }
 
+async fn establish_connect_tunnel_bypassed(
+    upgraded: Upgraded,
+    mut stream: TcpStream,
+    client_addr: SocketAddr,
+    addr: Address,
+) {
+    use tokio::io::{copy, split};
+
+    let (mut r, mut w) = split(upgraded);
+    let (mut svr_r, mut svr_w) = stream.split();
+
+    let rhalf = copy(&mut r, &mut svr_w);
+    let whalf = copy(&mut svr_r, &mut w);
+
+    debug!("CONNECT relay (bypassed) established {} <-> {}", client_addr, addr);
+
+    match future::select(rhalf, whalf).await {
+        Either::Left((Ok(..), _)) => trace!("CONNECT relay (bypassed) {} -> {} closed", client_addr, addr),
+        Either::Left((Err(err), _)) => trace!(
+            "CONNECT relay (bypassed) {} -> {} closed with error {:?}",
+            client_addr,
+            addr,
+            err,
+        ),
+        Either::Right((Ok(..), _)) => trace!("CONNECT relay (bypassed) {} <- {} closed", client_addr, addr),
+        Either::Right((Err(err), _)) => trace!(
+            "CONNECT relay (bypassed) {} <- {} closed with error {:?}",
+            client_addr,
+            addr,
+            err,
+        ),
+    }
+
+    debug!("CONNECT relay (bypassed) {} <-> {} closed", client_addr, addr);
+}
+
+/// Resolves a domain name to an `IpAddr`. Implementations are plugged into `DnsCache`, which
+/// adds the actual LRU + TTL caching on top.
+trait Resolver: Send + Sync {
+    fn resolve<'a>(&'a self, domain: &'a str) -> BoxFuture<'a, io::Result<IpAddr>>;
+}
+
+/// Resolves using the system resolver (the same getaddrinfo-backed lookup `TcpStream::connect`
+/// would use), via `tokio::net::lookup_host`. The default resolver.
+struct GaiResolver;
+
+impl Resolver for GaiResolver {
+    fn resolve<'a>(&'a self, domain: &'a str) -> BoxFuture<'a, io::Result<IpAddr>> {
+        async move { resolve_first_addr(&format!("{}:0", domain)).await.map(|addr| addr.ip()) }.boxed()
+    }
+}
+
+/// Resolves using `trust-dns-resolver`'s own stub, bypassing the system resolver entirely.
+#[cfg(feature = "trust-dns")]
+struct TrustDnsResolver(trust_dns_resolver::TokioAsyncResolver);
+
+#[cfg(feature = "trust-dns")]
+impl Resolver for TrustDnsResolver {
+    fn resolve<'a>(&'a self, domain: &'a str) -> BoxFuture<'a, io::Result<IpAddr>> {
+        async move {
+            self.0
+                .lookup_ip(domain)
+                .await
+                .map_err(|err| io::Error::new(io::ErrorKind::NotFound, err))?
+                .iter()
+                .next()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no records for {}", domain)))
+        }
+        .boxed()
+    }
+}
+
+/// Builds the `Resolver` selected by `kind`.
+fn build_resolver(kind: DnsResolverKind) -> Box<dyn Resolver> {
+    match kind {
+        DnsResolverKind::Gai => Box::new(GaiResolver),
+        #[cfg(feature = "trust-dns")]
+        DnsResolverKind::TrustDns => Box::new(TrustDnsResolver(
+            trust_dns_resolver::TokioAsyncResolver::tokio(
+                trust_dns_resolver::config::ResolverConfig::default(),
+                trust_dns_resolver::config::ResolverOpts::default(),
+            )
+            .expect("failed to build trust-dns resolver"),
+        )),
+    }
+}
+
+/// An LRU + TTL cache in front of a pluggable `Resolver`. Failed lookups are negatively cached
+/// too, for the same TTL, so a persistently-unresolvable domain doesn't cause a fresh lookup on
+/// every request.
+struct DnsCache {
+    resolver: Box<dyn Resolver>,
+    cache: Mutex<LruCache<String, (Option<IpAddr>, Instant)>>,
+    ttl: Duration,
+}
+
+impl DnsCache {
+    fn new(resolver: Box<dyn Resolver>, capacity: usize, ttl: Duration) -> DnsCache {
+        DnsCache {
+            resolver,
+            cache: Mutex::new(LruCache::new(capacity)),
+            ttl,
+        }
+    }
+
+    async fn resolve(&self, domain: &str) -> io::Result<IpAddr> {
+        if let Some((cached, cached_at)) = self.peek(domain) {
+            if cached_at.elapsed() < self.ttl {
+                return cached
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} is negatively cached", domain)));
+            }
+        }
+
+        let result = self.resolver.resolve(domain).await;
+
+        let mut cache = self.cache.lock().expect("dns cache lock poisoned");
+        cache.put(domain.to_owned(), (result.as_ref().ok().copied(), Instant::now()));
+
+        result
+    }
+
+    fn peek(&self, domain: &str) -> Option<(Option<IpAddr>, Instant)> {
+        let mut cache = self.cache.lock().expect("dns cache lock poisoned");
+        cache.get(domain).copied()
+    }
+}
+
+/// Resolves `addr` to its concrete `IpAddr` form through `dns_cache` when it is a domain name
+/// and a cache is configured; otherwise returns `addr` unchanged. This lets ACL/bypass
+/// decisions be made on the resolved IP while leaving the default (no `dns_cache` configured)
+/// behavior as pure name-based forwarding.
+async fn resolve_for_acl(addr: &Address, dns_cache: Option<&DnsCache>) -> Address {
+    if let (Address::DomainNameAddress(domain, port), Some(dns_cache)) = (addr, dns_cache) {
+        if let Ok(ip) = dns_cache.resolve(domain).await {
+            return Address::SocketAddress(SocketAddr::new(ip, *port));
+        }
+    }
+
+    addr.clone()
+}
+
+/// Outcome of an ACL/bypass decision: whether `addr` should skip the Shadowsocks server, and
+/// the (possibly DNS-resolved) `Address` the decision was made on. Callers that need to dial
+/// `addr` directly should dial this one, so they connect to the same IP the decision matched
+/// instead of re-resolving the name and risking a different answer (DNS round-robin).
+struct BypassDecision {
+    bypassed: bool,
+    resolved: Address,
+}
+
+/// Consults the context's ACL (if any) to decide whether `addr` should be connected to
+/// directly instead of being relayed through the Shadowsocks server.
+///
+/// Falls back to proxying (`bypassed` is `false`) when there is no ACL configured, or when
+/// the address doesn't match any rule.
+async fn check_target_bypassed(context: &SharedContext, addr: &Address, dns_cache: Option<&DnsCache>) -> BypassDecision {
+    let resolved = resolve_for_acl(addr, dns_cache).await;
+
+    let bypassed = match context.acl() {
+        Some(acl) => acl.check_target_bypassed(context, &resolved).await,
+        None => false,
+    };
+
+    BypassDecision { bypassed, resolved }
+}
+
+/// Compares two byte strings in constant time, so a wrong proxy password can't be distinguished
+/// from a correct one by how long the comparison takes.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Checks the `Proxy-Authorization: Basic ...` header on `req` against the configured list of
+/// `(username, password)` credentials.
+///
+/// Returns `true` when no credentials are configured (proxy auth disabled) or when the header
+/// matches one of the configured credentials.
+fn check_proxy_authorization(req: &Request<Body>, credentials: &[(String, String)]) -> bool {
+    if credentials.is_empty() {
+        return true;
+    }
+
+    let header = match req.headers().get(hyper::header::PROXY_AUTHORIZATION) {
+        Some(h) => h,
+        None => return false,
+    };
+
+    let header = match header.to_str() {
+        Ok(h) => h,
+        Err(..) => return false,
+    };
+
+    let encoded = match header.strip_prefix("Basic ") {
+        Some(encoded) => encoded,
+        None => return false,
+    };
+
+    let decoded = match base64::decode(encoded) {
+        Ok(d) => d,
+        Err(..) => return false,
+    };
+
+    let decoded = match String::from_utf8(decoded) {
+        Ok(s) => s,
+        Err(..) => return false,
+    };
+
+    let (user, pass) = match decoded.split_once(':') {
+        Some(pair) => pair,
+        None => return false,
+    };
+
+    credentials.iter().any(|(cred_user, cred_pass)| {
+        constant_time_eq(cred_user.as_bytes(), user.as_bytes()) && constant_time_eq(cred_pass.as_bytes(), pass.as_bytes())
+    })
+}
+
+fn proxy_authentication_required_response() -> Response<Body> {
+    let mut resp = Response::new(Body::from("Proxy Authentication Required"));
+    *resp.status_mut() = StatusCode::PROXY_AUTHENTICATION_REQUIRED;
+    resp.headers_mut().insert(
+        hyper::header::PROXY_AUTHENTICATE,
+        hyper::header::HeaderValue::from_static("Basic realm=\"shadowsocks\""),
+    );
+    resp
+}
+
+/// Strips the `Proxy-Authorization` and `Proxy-Connection` hop-by-hop headers so they don't
+/// leak to the origin server.
+fn strip_proxy_headers(req: &mut Request<Body>) {
+    req.headers_mut().remove(hyper::header::PROXY_AUTHORIZATION);
+    req.headers_mut().remove("Proxy-Connection");
+}
+
 type ShadowSocksClient = Client<ShadowSocksConnector>;
+type BypassHttpClient = Client<HttpConnector>;
+
+/// Per-request dependencies shared across every connection handled by a single `run()` server,
+/// bundled so `server_dispatch` takes one argument instead of growing with every request.
+#[derive(Clone)]
+struct ServerDeps {
+    client: ShadowSocksClient,
+    bypass_client: BypassHttpClient,
+    pool: Arc<CryptoConnectionPool>,
+    dns_cache: Option<Arc<DnsCache>>,
+}
 
 async fn server_dispatch(
     context: SharedContext,
     req: Request<Body>,
     svr_cfg: Arc<ServerConfig>,
     client_addr: SocketAddr,
-    client: ShadowSocksClient,
+    deps: ServerDeps,
 ) -> Result<Response<Body>, io::Error> {
+    let ServerDeps { client, bypass_client, pool, dns_cache } = deps;
+    let credentials = context.config().http_proxy_auth.as_deref().unwrap_or(&[]);
+    if !check_proxy_authorization(&req, credentials) {
+        debug!("HTTP {} {} rejected, bad or missing Proxy-Authorization", req.method(), req.uri());
+
+        return Ok(proxy_authentication_required_response());
+    }
+
     // Parse URI
     //
     // Proxy request URI must contains a host
@@ -263,17 +764,81 @@ async fn server_dispatch(
         Some(h) => h,
     };
 
+    let decision = check_target_bypassed(&context, &host, dns_cache.as_deref()).await;
+    let bypassed = decision.bypassed;
+
     if Method::CONNECT == req.method() {
         // Establish a TCP tunnel
         // https://tools.ietf.org/html/draft-luotonen-web-proxy-tunneling-01
 
-        debug!("HTTP CONNECT {}", host);
+        debug!("HTTP CONNECT {} (bypassed: {})", host, bypassed);
+
+        if bypassed {
+            // Direct connect, skipping the Shadowsocks server entirely. Dial the address the
+            // bypass decision was made on, rather than re-resolving `host` and risking a
+            // different answer than the one the ACL/bypass rule matched.
+            let source_bind = context.config().source_bind.as_ref();
+            let direct_connect = async {
+                let addr = dial_addr(&decision.resolved).await?;
+                connect_with_source_bind(addr, source_bind).await
+            };
+
+            let stream = match direct_connect.await {
+                Ok(s) => s,
+                Err(err) => {
+                    error!("Failed to connect directly to {}, error: {}", host, err);
+
+                    let mut resp = Response::new(Body::from(format!("Failed to connect to {}", host)));
+                    *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+
+                    return Ok(resp);
+                }
+            };
+
+            debug!("CONNECT relay (bypassed) connected {} <-> {}", client_addr, host);
 
-        // Connect to Shadowsocks' remote
+            tokio::spawn(async move {
+                match req.into_body().on_upgrade().await {
+                    Ok(upgraded) => {
+                        trace!(
+                            "CONNECT tunnel (bypassed) upgrade success, {} <-> {}",
+                            client_addr,
+                            host
+                        );
+
+                        establish_connect_tunnel_bypassed(upgraded, stream, client_addr, host).await
+                    }
+                    Err(e) => {
+                        error!(
+                            "Failed to upgrade TCP tunnel (bypassed) {} <-> {}, error: {}",
+                            client_addr, host, e
+                        );
+                    }
+                }
+            });
+
+            let resp = Response::builder()
+                .header("Proxy-Agent", format!("ShadowSocks/{}", crate::VERSION))
+                .body(Body::empty())
+                .unwrap();
+
+            return Ok(resp);
+        }
+
+        // Connect to Shadowsocks' remote, reusing a pooled, not-yet-consumed connection
+        // for this (server, target) pair when one is available.
         //
         // FIXME: What STATUS should I return for connection error?
-        let stream = super::connect_proxy_server(&*context, &*svr_cfg).await?;
-        let stream = super::proxy_server_handshake(stream, svr_cfg.clone(), &host).await?;
+        let key = pool_key(&svr_cfg, &host);
+        let stream = match pool.take(&key) {
+            Some(stream) => stream,
+            None => {
+                let stream = connect_proxy_server_upstream_aware(&context, &*svr_cfg).await?;
+                let stream = super::proxy_server_handshake(stream, svr_cfg.clone(), &host).await?;
+                prewarm_pool(context.clone(), svr_cfg.clone(), host.clone(), pool.clone(), key.clone());
+                stream
+            }
+        };
 
         debug!(
             "CONNECT relay connected {} <-> {} ({})",
@@ -307,6 +872,10 @@ async fn server_dispatch(
                         host,
                         e
                     );
+
+                    // The handshaked stream was never consumed, so it's still good to use
+                    // for the next request to the same target.
+                    pool.put(key, stream);
                 }
             }
         });
@@ -320,9 +889,18 @@ async fn server_dispatch(
     } else {
         let method = req.method().clone();
 
-        debug!("HTTP {} {}", method, host);
+        debug!("HTTP {} {} (bypassed: {})", method, host, bypassed);
 
-        let res = match client.request(req).await {
+        let mut req = req;
+        strip_proxy_headers(&mut req);
+
+        let res = if bypassed {
+            bypass_client.request(req).await
+        } else {
+            client.request(req).await
+        };
+
+        let res = match res {
             Ok(res) => res,
             Err(err) => {
                 error!(
@@ -353,6 +931,103 @@ async fn server_dispatch(
     }
 }
 
+/// Builds a `TlsAcceptor` from `config`'s PEM-encoded cert/key pair.
+fn build_tls_acceptor(config: &TlsConfig) -> io::Result<TlsAcceptor> {
+    let mut cert_reader = BufReader::new(File::open(&config.cert_path)?);
+    let certs = rustls_pemfile::certs(&mut cert_reader)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid TLS certificate"))?
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect();
+
+    let mut key_reader = BufReader::new(File::open(&config.key_path)?);
+    let mut keys = rustls_pemfile::pkcs8_private_keys(&mut key_reader)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid TLS private key"))?;
+    if keys.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "no PKCS#8 private key found"));
+    }
+    let key = rustls::PrivateKey(keys.remove(0));
+
+    let tls_config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+
+    Ok(TlsAcceptor::from(Arc::new(tls_config)))
+}
+
+/// A TLS-wrapped client connection, tracking the original peer address the same way
+/// `hyper::server::conn::AddrStream` does for plaintext connections.
+#[pin_project]
+struct TlsAddrStream {
+    #[pin]
+    stream: TlsStream<TcpStream>,
+    remote_addr: SocketAddr,
+}
+
+impl TlsAddrStream {
+    fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+}
+
+impl AsyncRead for TlsAddrStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        self.project().stream.poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TlsAddrStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.project().stream.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        self.project().stream.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        self.project().stream.poll_shutdown(cx)
+    }
+}
+
+/// Accepts plain TCP connections on `listener` and upgrades each one to TLS using `acceptor`,
+/// yielding them as a `Stream` hyper can serve via `hyper::server::accept::from_stream`.
+///
+/// The TLS handshake for each connection runs in its own task so a slow or stalled client can't
+/// block accepting everyone else; a failed handshake is logged and the connection dropped
+/// instead of ending the whole incoming stream.
+fn tls_incoming(listener: TcpListener, acceptor: TlsAcceptor) -> impl Stream<Item = io::Result<TlsAddrStream>> {
+    let (tx, rx) = mpsc::channel(DEFAULT_TLS_ACCEPT_BACKLOG);
+
+    tokio::spawn(async move {
+        loop {
+            let (tcp_stream, remote_addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    error!("HTTPS proxy listener accept failed: {}", err);
+                    tokio::time::sleep(TLS_ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            };
+
+            let acceptor = acceptor.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                match acceptor.accept(tcp_stream).await {
+                    Ok(stream) => {
+                        let _ = tx.send(TlsAddrStream { stream, remote_addr }).await;
+                    }
+                    Err(err) => debug!("TLS handshake with {} failed: {}", remote_addr, err),
+                }
+            });
+        }
+    });
+
+    ReceiverStream::new(rx).map(Ok)
+}
+
 /// Starts a TCP local server with HTTP proxy protocol
 pub async fn run(context: SharedContext) -> io::Result<()> {
     let local_addr = *context.config().local.as_ref().expect("Missing local config");
@@ -361,41 +1036,247 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
 
     let mut server_clients = HashMap::new();
 
+    // Shared pool of handshaked-but-unconsumed connections, reused across all remote servers
+    let pool = Arc::new(ConnectionPool::new(DEFAULT_POOL_MAX_IDLE_PER_KEY, DEFAULT_POOL_IDLE_TIMEOUT));
+
     // Create HTTP clients for each remote servers
     for svr_cfg in servers.servers() {
         let addr_str = svr_cfg.addr().to_string();
-        let client = Client::builder().build::<_, Body>(ShadowSocksConnector::new(context.clone(), svr_cfg));
+        let client =
+            Client::builder().build::<_, Body>(ShadowSocksConnector::new(context.clone(), svr_cfg, pool.clone()));
         server_clients.insert(addr_str, client);
     }
 
-    let make_service = make_service_fn(|socket: &AddrStream| {
-        let client_addr = socket.remote_addr();
-        let svr_cfg = servers.pick_server();
-        let context = context.clone();
+    // Shared client for ACL-bypassed (direct-connect) requests
+    let bypass_client = Client::builder().build::<_, Body>(HttpConnector::new());
 
-        // Keep connections for clients
-        let addr_str = svr_cfg.addr().to_string();
-        let client = server_clients.get(&addr_str).unwrap().clone();
+    // Resolver + cache for domain names seen in `host_addr`, used to make ACL/bypass decisions
+    // on the resolved IP. `None` preserves the default, purely name-based forwarding behavior.
+    let dns_cache = match context.config().dns_cache.as_ref() {
+        Some(settings) => Some(Arc::new(DnsCache::new(
+            build_resolver(settings.resolver),
+            settings.capacity,
+            settings.ttl,
+        ))),
+        None => None,
+    };
 
-        async move {
-            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
-                server_dispatch(context.clone(), req, svr_cfg.clone(), client_addr, client.clone())
-            }))
+    if let Some(tls_config) = context.config().http_tls.as_ref() {
+        let acceptor = build_tls_acceptor(tls_config)?;
+        let listener = TcpListener::bind(&local_addr).await?;
+        let actual_local_addr = listener.local_addr()?;
+
+        let make_service = make_service_fn(|socket: &TlsAddrStream| {
+            let client_addr = socket.remote_addr();
+            let svr_cfg = servers.pick_server();
+            let context = context.clone();
+
+            let addr_str = svr_cfg.addr().to_string();
+            let deps = ServerDeps {
+                client: server_clients.get(&addr_str).unwrap().clone(),
+                bypass_client: bypass_client.clone(),
+                pool: pool.clone(),
+                dns_cache: dns_cache.clone(),
+            };
+
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    server_dispatch(context.clone(), req, svr_cfg.clone(), client_addr, deps.clone())
+                }))
+            }
+        });
+
+        let server = Server::builder(accept::from_stream(tls_incoming(listener, acceptor))).serve(make_service);
+
+        info!("ShadowSocks HTTPS Listening on {}", actual_local_addr);
+
+        if let Err(err) = server.await {
+            error!("Hyper Server error: {}", err);
+            return Err(io::Error::new(io::ErrorKind::Other, err));
         }
-    });
+    } else {
+        let make_service = make_service_fn(|socket: &AddrStream| {
+            let client_addr = socket.remote_addr();
+            let svr_cfg = servers.pick_server();
+            let context = context.clone();
+
+            // Keep connections for clients
+            let addr_str = svr_cfg.addr().to_string();
+            let deps = ServerDeps {
+                client: server_clients.get(&addr_str).unwrap().clone(),
+                bypass_client: bypass_client.clone(),
+                pool: pool.clone(),
+                dns_cache: dns_cache.clone(),
+            };
 
-    let server = Server::bind(&local_addr).serve(make_service);
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    server_dispatch(context.clone(), req, svr_cfg.clone(), client_addr, deps.clone())
+                }))
+            }
+        });
 
-    let actual_local_addr = server.local_addr();
+        let server = Server::bind(&local_addr).serve(make_service);
 
-    info!("ShadowSocks HTTP Listening on {}", actual_local_addr);
+        let actual_local_addr = server.local_addr();
 
-    if let Err(err) = server.await {
-        use std::io::{Error, ErrorKind};
+        info!("ShadowSocks HTTP Listening on {}", actual_local_addr);
 
-        error!("Hyper Server error: {}", err);
-        return Err(Error::new(ErrorKind::Other, err));
+        if let Err(err) = server.await {
+            error!("Hyper Server error: {}", err);
+            return Err(io::Error::new(io::ErrorKind::Other, err));
+        }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{check_proxy_authorization, ConnectionPool, DnsCache, Resolver};
+    use futures::future::{BoxFuture, FutureExt};
+    use hyper::{Body, Request};
+    use std::{
+        io,
+        net::{IpAddr, Ipv4Addr},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    fn request_with_proxy_auth(value: Option<&str>) -> Request<Body> {
+        let mut builder = Request::builder();
+        if let Some(value) = value {
+            builder = builder.header(hyper::header::PROXY_AUTHORIZATION, value);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn no_credentials_configured_allows_any_request() {
+        let req = request_with_proxy_auth(None);
+        assert!(check_proxy_authorization(&req, &[]));
+    }
+
+    #[test]
+    fn missing_header_rejected_when_credentials_configured() {
+        let creds = [("alice".to_owned(), "hunter2".to_owned())];
+        let req = request_with_proxy_auth(None);
+        assert!(!check_proxy_authorization(&req, &creds));
+    }
+
+    #[test]
+    fn matching_credentials_accepted() {
+        let creds = [("alice".to_owned(), "hunter2".to_owned())];
+        let header = format!("Basic {}", base64::encode("alice:hunter2"));
+        let req = request_with_proxy_auth(Some(&header));
+        assert!(check_proxy_authorization(&req, &creds));
+    }
+
+    #[test]
+    fn wrong_password_rejected() {
+        let creds = [("alice".to_owned(), "hunter2".to_owned())];
+        let header = format!("Basic {}", base64::encode("alice:wrong"));
+        let req = request_with_proxy_auth(Some(&header));
+        assert!(!check_proxy_authorization(&req, &creds));
+    }
+
+    #[test]
+    fn malformed_header_rejected() {
+        let creds = [("alice".to_owned(), "hunter2".to_owned())];
+        let req = request_with_proxy_auth(Some("Basic not-base64!"));
+        assert!(!check_proxy_authorization(&req, &creds));
+    }
+
+    #[test]
+    fn take_returns_none_when_empty() {
+        let pool = ConnectionPool::<i32>::new(2, Duration::from_secs(60));
+        assert_eq!(pool.take("k"), None);
+    }
+
+    #[test]
+    fn put_then_take_round_trips() {
+        let pool = ConnectionPool::<i32>::new(2, Duration::from_secs(60));
+        pool.put("k".to_owned(), 1);
+        assert_eq!(pool.take("k"), Some(1));
+        assert_eq!(pool.take("k"), None);
+    }
+
+    #[test]
+    fn put_evicts_beyond_capacity() {
+        let pool = ConnectionPool::<i32>::new(2, Duration::from_secs(60));
+        pool.put("k".to_owned(), 1);
+        pool.put("k".to_owned(), 2);
+        pool.put("k".to_owned(), 3); // pool is full, dropped
+
+        assert_eq!(pool.take("k"), Some(1));
+        assert_eq!(pool.take("k"), Some(2));
+        assert_eq!(pool.take("k"), None);
+    }
+
+    #[test]
+    fn take_skips_expired_entries() {
+        let pool = ConnectionPool::<i32>::new(2, Duration::from_millis(10));
+        pool.put("k".to_owned(), 1);
+        std::thread::sleep(Duration::from_millis(30));
+        pool.put("k".to_owned(), 2);
+
+        assert_eq!(pool.take("k"), Some(2));
+        assert_eq!(pool.take("k"), None);
+    }
+
+    struct CountingResolver {
+        calls: Arc<AtomicUsize>,
+        result: Option<IpAddr>,
+    }
+
+    impl Resolver for CountingResolver {
+        fn resolve<'a>(&'a self, _domain: &'a str) -> BoxFuture<'a, io::Result<IpAddr>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let result = self.result;
+            async move { result.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such host")) }.boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn dns_cache_reuses_cached_result_within_ttl() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let resolver = CountingResolver {
+            calls: calls.clone(),
+            result: Some(IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34))),
+        };
+        let cache = DnsCache::new(Box::new(resolver), 8, Duration::from_secs(60));
+
+        assert!(cache.resolve("example.com").await.is_ok());
+        assert!(cache.resolve("example.com").await.is_ok());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn dns_cache_negatively_caches_failed_lookups() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let resolver = CountingResolver { calls: calls.clone(), result: None };
+        let cache = DnsCache::new(Box::new(resolver), 8, Duration::from_secs(60));
+
+        assert!(cache.resolve("missing.example").await.is_err());
+        assert!(cache.resolve("missing.example").await.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn dns_cache_expires_entries_after_ttl() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let resolver = CountingResolver {
+            calls: calls.clone(),
+            result: Some(IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34))),
+        };
+        let cache = DnsCache::new(Box::new(resolver), 8, Duration::from_millis(10));
+
+        assert!(cache.resolve("example.com").await.is_ok());
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert!(cache.resolve("example.com").await.is_ok());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}